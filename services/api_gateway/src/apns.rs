@@ -0,0 +1,327 @@
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use futures_util::stream::StreamExt;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use lapin::{
+    options::{BasicAckOptions, BasicConsumeOptions, BasicNackOptions},
+    types::FieldTable,
+    Channel,
+};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::models::{NotificationStatus, UpdateStatusRequest};
+
+// Apple rejects provider tokens older than 1h and throttles too-frequent
+// regeneration, so we cache and only re-sign past this age.
+const TOKEN_MAX_AGE: Duration = Duration::from_secs(50 * 60);
+
+#[derive(Clone)]
+pub struct ApnsConfig {
+    pub team_id: String,
+    pub key_id: String,
+    pub bundle_id: String,
+    pub key_pem: String,
+    pub sandbox: bool,
+}
+
+impl ApnsConfig {
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            team_id: env::var("APNS_TEAM_ID").context("APNS_TEAM_ID not set")?,
+            key_id: env::var("APNS_KEY_ID").context("APNS_KEY_ID not set")?,
+            bundle_id: env::var("APNS_BUNDLE_ID").context("APNS_BUNDLE_ID not set")?,
+            key_pem: env::var("APNS_KEY_PEM").context("APNS_KEY_PEM not set")?,
+            sandbox: env::var("APNS_SANDBOX").map(|v| v == "true").unwrap_or(false),
+        })
+    }
+
+    fn host(&self) -> &'static str {
+        if self.sandbox {
+            "https://api.sandbox.push.apple.com"
+        } else {
+            "https://api.push.apple.com"
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Claims {
+    iss: String,
+    iat: i64,
+}
+
+struct CachedToken {
+    jwt: String,
+    generated_at: Instant,
+}
+
+/// Signs and caches the ES256 provider JWT APNs expects as a bearer token.
+pub struct APNsToken {
+    team_id: String,
+    key_id: String,
+    encoding_key: EncodingKey,
+    cached: Mutex<Option<CachedToken>>,
+}
+
+impl APNsToken {
+    pub fn new(cfg: &ApnsConfig) -> Result<Self> {
+        let encoding_key = EncodingKey::from_ec_pem(cfg.key_pem.as_bytes())
+            .context("invalid APNs ES256 provider key")?;
+        Ok(Self {
+            team_id: cfg.team_id.clone(),
+            key_id: cfg.key_id.clone(),
+            encoding_key,
+            cached: Mutex::new(None),
+        })
+    }
+
+    /// Returns the cached bearer token, signing a fresh one if it's missing
+    /// or older than `TOKEN_MAX_AGE`.
+    pub fn get(&self) -> Result<String> {
+        let mut cached = self.cached.lock().unwrap();
+        if let Some(tok) = cached.as_ref() {
+            if tok.generated_at.elapsed() < TOKEN_MAX_AGE {
+                return Ok(tok.jwt.clone());
+            }
+        }
+
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(self.key_id.clone());
+        let claims = Claims {
+            iss: self.team_id.clone(),
+            iat: chrono::Utc::now().timestamp(),
+        };
+        let jwt = encode(&header, &claims, &self.encoding_key).context("failed to sign APNs jwt")?;
+
+        *cached = Some(CachedToken {
+            jwt: jwt.clone(),
+            generated_at: Instant::now(),
+        });
+        Ok(jwt)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ApnsErrorBody {
+    reason: String,
+}
+
+/// Typed APNs HTTP/2 error reasons, per Apple's `{reason}` error body.
+#[derive(Debug)]
+pub enum ApnsError {
+    Unregistered,
+    BadDeviceToken,
+    Other(String),
+}
+
+impl ApnsError {
+    fn from_reason(reason: &str) -> Self {
+        match reason {
+            "Unregistered" => ApnsError::Unregistered,
+            "BadDeviceToken" => ApnsError::BadDeviceToken,
+            other => ApnsError::Other(other.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for ApnsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApnsError::Unregistered => write!(f, "Unregistered"),
+            ApnsError::BadDeviceToken => write!(f, "BadDeviceToken"),
+            ApnsError::Other(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ApnsClient {
+    http: Client,
+    cfg: ApnsConfig,
+    token: Arc<APNsToken>,
+}
+
+impl ApnsClient {
+    pub fn new(cfg: ApnsConfig) -> Result<Self> {
+        let token = APNsToken::new(&cfg)?;
+        let http = Client::builder().http2_prior_knowledge().build()?;
+        Ok(Self {
+            http,
+            cfg,
+            token: Arc::new(token),
+        })
+    }
+
+    /// Sends one push to `device_token`. `Ok(())` on Apple's 200; on any
+    /// other status the JSON `{reason}` body is parsed into [`ApnsError`].
+    pub async fn send(&self, device_token: &str, payload: &Value) -> Result<(), ApnsError> {
+        let jwt = self.token.get().map_err(|e| ApnsError::Other(e.to_string()))?;
+        let url = format!("{}/3/device/{}", self.cfg.host(), device_token);
+
+        let resp = self
+            .http
+            .post(&url)
+            .header("authorization", format!("bearer {jwt}"))
+            .header("apns-topic", &self.cfg.bundle_id)
+            .header("apns-push-type", "alert")
+            .header("apns-priority", "10")
+            .json(payload)
+            .send()
+            .await
+            .map_err(|e| ApnsError::Other(format!("apns_unreachable: {e}")))?;
+
+        if resp.status().is_success() {
+            return Ok(());
+        }
+
+        let body: ApnsErrorBody = resp
+            .json()
+            .await
+            .unwrap_or(ApnsErrorBody { reason: "unknown".into() });
+        Err(ApnsError::from_reason(&body.reason))
+    }
+}
+
+#[derive(Serialize)]
+struct RenderTemplateRequest<'a> {
+    template_code: &'a str,
+    variables: &'a Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct RenderedTemplate {
+    title: String,
+    body: String,
+}
+
+/// Resolves `template_code` against the template service so the APNs
+/// payload carries real alert text instead of the raw template variables.
+async fn render_push_template(
+    http: &Client,
+    template_svc_url: &str,
+    template_code: &str,
+    variables: &Value,
+) -> Result<RenderedTemplate> {
+    let url = format!("{template_svc_url}/api/v1/templates/render");
+    let resp = http
+        .post(&url)
+        .json(&RenderTemplateRequest { template_code, variables })
+        .send()
+        .await
+        .context("template service unreachable")?;
+    resp.json::<RenderedTemplate>().await.context("invalid template render response")
+}
+
+async fn report_push_status(
+    client: &Client,
+    gateway_base_url: &str,
+    request_id: &str,
+    status: NotificationStatus,
+    error: Option<String>,
+) {
+    let update = UpdateStatusRequest {
+        notification_id: request_id.to_string(),
+        status,
+        timestamp: Some(chrono::Utc::now().to_rfc3339()),
+        error,
+    };
+    let url = format!("{gateway_base_url}/api/v1/push/status/");
+    if let Err(e) = client.post(&url).json(&update).send().await {
+        eprintln!("failed to post push status for {request_id}: {e}");
+    }
+}
+
+/// Consumes `push.queue` and delivers each message to APNs, posting the
+/// outcome back to `/api/v1/push/status/` the same way other workers do.
+pub async fn run_push_consumer(
+    channel: Channel,
+    client: ApnsClient,
+    gateway_base_url: String,
+    template_svc_url: String,
+) -> Result<()> {
+    let mut consumer = channel
+        .basic_consume(
+            crate::PUSH_QUEUE,
+            "apns-worker",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let status_client = Client::new();
+    while let Some(delivery) = consumer.next().await {
+        let delivery = match delivery {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("push.queue consume error: {e}");
+                continue;
+            }
+        };
+
+        let msg: Value = match serde_json::from_slice(&delivery.data) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("push.queue bad payload: {e}");
+                let _ = delivery
+                    .nack(BasicNackOptions { requeue: false, ..Default::default() })
+                    .await;
+                continue;
+            }
+        };
+
+        let request_id = msg["request_id"].as_str().unwrap_or_default().to_string();
+        let device_token = msg["variables"]["meta"]["device_token"].as_str().unwrap_or_default();
+        let template_code = msg["template_code"].as_str().unwrap_or_default();
+
+        let rendered = match render_push_template(&status_client, &template_svc_url, template_code, &msg["variables"]).await {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("template render failed for {request_id}, nacking for retry: {e}");
+                let _ = delivery
+                    .nack(BasicNackOptions { requeue: false, ..Default::default() })
+                    .await;
+                continue;
+            }
+        };
+
+        let push_payload = serde_json::json!({
+            "aps": {
+                "alert": { "title": rendered.title, "body": rendered.body },
+                "sound": "default",
+            },
+        });
+
+        match client.send(device_token, &push_payload).await {
+            Ok(()) => {
+                report_push_status(&status_client, &gateway_base_url, &request_id, NotificationStatus::Delivered, None)
+                    .await;
+                let _ = delivery.ack(BasicAckOptions::default()).await;
+            }
+            Err(e @ (ApnsError::Unregistered | ApnsError::BadDeviceToken)) => {
+                // Permanent: retrying won't help a dead/invalid token, so
+                // report failed directly and remove it from the queue.
+                report_push_status(&status_client, &gateway_base_url, &request_id, NotificationStatus::Failed, Some(e.to_string()))
+                    .await;
+                let _ = delivery.ack(BasicAckOptions::default()).await;
+            }
+            Err(e) => {
+                // Retryable: nack (don't requeue onto push.queue itself) so
+                // it dead-letters into the chunk0-2 retry/backoff pipeline
+                // instead of being marked failed on the first transient error.
+                eprintln!("apns send failed for {request_id}, nacking for retry: {e}");
+                let _ = delivery
+                    .nack(BasicNackOptions { requeue: false, ..Default::default() })
+                    .await;
+            }
+        }
+    }
+
+    Ok(())
+}
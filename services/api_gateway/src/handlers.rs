@@ -1,5 +1,7 @@
-use actix_web::{web, HttpResponse, http::StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, http::StatusCode};
+use futures_util::StreamExt;
 use serde_json::{json, Value};
+use std::pin::Pin;
 use uuid::Uuid;
 
 use crate::models::{
@@ -14,6 +16,7 @@ use crate::status::StatusStore;
 use lapin::{
     options::BasicPublishOptions,
     protocol::basic::AMQPProperties,
+    publisher_confirm::Confirmation,
 };
 
 #[derive(Clone)]
@@ -24,6 +27,7 @@ pub struct AppState {
     pub template_svc_url: String,    // workers use it; kept for completeness
     pub status_store: StatusStore,
     pub amqp_ready: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    pub max_priority: u8,
 }
 
 pub async fn health() -> HttpResponse {
@@ -31,7 +35,7 @@ pub async fn health() -> HttpResponse {
 }
 
 pub async fn ready(state: web::Data<AppState>) -> HttpResponse {
-    let _ = state.status_store.set_status("ready_probe", "ok").await;
+    let _ = state.status_store.set_status("ready_probe", "ok", None).await;
     HttpResponse::Ok().json(json!({ "status": "ok" }))
 }
 
@@ -65,19 +69,42 @@ pub async fn create_notification(
     state: web::Data<AppState>,
     body: web::Json<CreateNotificationRequest>,
 ) -> HttpResponse {
-    let req = body.into_inner();
+    let (status, envelope) = create_notification_core(&state, body.into_inner()).await;
+    HttpResponse::build(status).json(envelope)
+}
+
+/// Shared by the REST `create_notification` handler and the JSON-RPC
+/// `notifications.create` method, so both reserve/commit/rollback the same
+/// idempotency key and publish through the same confirmed-publish path.
+pub async fn create_notification_core(
+    state: &AppState,
+    req: CreateNotificationRequest,
+) -> (StatusCode, Envelope<Value>) {
+    if req.priority < 0 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Envelope::<Value>::err(
+                "invalid_priority",
+                &format!("priority must be between 0 and {}", state.max_priority),
+            ),
+        );
+    }
+    let priority = (req.priority as u32).min(state.max_priority as u32) as u8;
 
     // Idempotency guard
     match state.status_store.reserve_idem(&req.request_id).await {
         Ok(true) => {}
         Ok(false) => {
-            return HttpResponse::Accepted().json(
-                Envelope::<Value>::ok("duplicate_request", json!({ "notification_id": req.request_id }))
+            return (
+                StatusCode::ACCEPTED,
+                Envelope::<Value>::ok("duplicate_request", json!({ "notification_id": req.request_id })),
             )
         }
         Err(_) => {
-            return HttpResponse::InternalServerError()
-                .json(Envelope::<Value>::err("idempotency_error", "redis_error"))
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Envelope::<Value>::err("idempotency_error", "redis_error"),
+            )
         }
     }
 
@@ -96,7 +123,7 @@ pub async fn create_notification(
         "user_id": req.user_id,
         "template_code": req.template_code,
         "variables": req.variables,
-        "priority": req.priority,
+        "priority": priority,
         "metadata": req.metadata,
         "attempt": 0,
         "max_attempts": 3,
@@ -107,14 +134,20 @@ pub async fn create_notification(
     let payload = match serde_json::to_vec(&msg) {
         Ok(p) => p,
         Err(e) => {
-            return HttpResponse::InternalServerError().json(Envelope::<Value>::err(
-                "serialize_error",
-                &format!("failed to serialize publish payload: {e}"),
-            ))
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Envelope::<Value>::err("serialize_error", &format!("failed to serialize publish payload: {e}")),
+            )
         }
     };
 
-    let publish_res = state
+    // Confirmed publish: await both the broker ack of the publish itself and
+    // the publisher-confirm, so a failure rolls back the idempotency key
+    // instead of leaving it permanently reserved. The channel is put into
+    // confirm mode once in main.rs; a Nack or a returned (unroutable,
+    // `mandatory: true`) message counts as a failure here too, not just a
+    // transport-level error.
+    let publish_res = match state
         .amqp_channel
         .basic_publish(
             &state.exchange_name,
@@ -124,26 +157,37 @@ pub async fn create_notification(
             AMQPProperties::default()
                 .with_correlation_id(correlation_id.clone().into())
                 .with_message_id(Uuid::new_v4().to_string().into())
-                .with_content_type("application/json".into()),
+                .with_content_type("application/json".into())
+                .with_priority(priority),
         )
-        .await;
+        .await
+    {
+        Ok(confirm) => match confirm.await {
+            Ok(Confirmation::Ack(None)) => Ok(()),
+            Ok(Confirmation::Ack(Some(_))) => Err(anyhow::anyhow!("message returned as unroutable")),
+            Ok(Confirmation::Nack(_)) => Err(anyhow::anyhow!("broker nacked publish")),
+            Ok(Confirmation::NotRequested) => Err(anyhow::anyhow!("channel not in confirm mode")),
+            Err(e) => Err(e.into()),
+        },
+        Err(e) => Err(e.into()),
+    };
 
     if publish_res.is_err() {
-        let _ = state
-            .status_store
-            .set_status(msg["request_id"].as_str().unwrap_or_default(), "failed")
-            .await;
-        return HttpResponse::BadGateway()
-            .json(Envelope::<Value>::err("queue_publish_failed", "rabbitmq_error"));
+        let _ = state.status_store.rollback_idem(&req.request_id).await;
+        let _ = state.status_store.set_status(&req.request_id, "failed", Some(routing_key)).await;
+        return (
+            StatusCode::BAD_GATEWAY,
+            Envelope::<Value>::err("queue_publish_failed", "rabbitmq_error"),
+        );
     }
 
-    let _ = state
-        .status_store
-        .set_status(msg["request_id"].as_str().unwrap_or_default(), "pending")
-        .await;
+    let _ = state.status_store.commit_idem(&req.request_id).await;
+    let _ = state.status_store.set_status(&req.request_id, "pending", Some(routing_key)).await;
 
-    HttpResponse::Accepted()
-        .json(Envelope::<Value>::ok("queued", json!({ "notification_id": msg["request_id"] })))
+    (
+        StatusCode::ACCEPTED,
+        Envelope::<Value>::ok("queued", json!({ "notification_id": msg["request_id"] })),
+    )
 }
 
 // Workers post back here:
@@ -178,7 +222,7 @@ async fn update_status_impl(
         NotificationStatus::Failed    => "failed",
     };
 
-    if let Err(_) = state.status_store.set_status(&req.notification_id, state_str).await {
+    if let Err(_) = state.status_store.set_status(&req.notification_id, state_str, Some(channel)).await {
         return HttpResponse::InternalServerError()
             .json(Envelope::<Value>::err("status_update_failed", "redis_error"));
     }
@@ -194,3 +238,90 @@ async fn update_status_impl(
         }),
     ))
 }
+
+// GET /api/v1/notifications/{id}/events
+//
+// Streams status transitions for `id` as Server-Sent Events instead of
+// making callers poll. Forwards the `x-correlation-id` the `CorrelationId`
+// middleware attached, and closes once a terminal `delivered`/`failed`
+// event is seen.
+pub async fn stream_notification_events(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    http_req: HttpRequest,
+) -> HttpResponse {
+    let notification_id = path.into_inner();
+    let correlation_id = http_req
+        .headers()
+        .get("x-correlation-id")
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string())
+        .unwrap_or_default();
+
+    let events = match state.status_store.subscribe().await {
+        Ok(s) => s,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(Envelope::<Value>::err(
+                "subscribe_failed",
+                &format!("failed to subscribe to status events: {e}"),
+            ))
+        }
+    };
+
+    // Check the status that's already in Redis *after* subscribing, so a
+    // transition racing with the subscribe call is still caught by the
+    // stream below. This only covers the notification having already
+    // reached a terminal state before the client ever connected (e.g. a
+    // fast-completing email, or a client re-opening the stream after
+    // polling once) — without it `set_status` never fires again and the
+    // response hangs open with no data and no close.
+    let precheck = state.status_store.get_status(&notification_id).await.ok().flatten();
+
+    if let Some(status) = precheck.filter(|s| matches!(s.as_str(), "delivered" | "failed")) {
+        let event = json!({
+            "notification_id": notification_id,
+            "status": status,
+            "channel": Value::Null,
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+        });
+        let sse = format!("id: {correlation_id}\nevent: status\ndata: {event}\n\n");
+        let body: Pin<Box<dyn futures_util::Stream<Item = Result<web::Bytes, actix_web::Error>> + Send>> =
+            Box::pin(futures_util::stream::once(async move { Ok(web::Bytes::from(sse)) }));
+        return HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(("cache-control", "no-cache"))
+            .streaming(body);
+    }
+
+    let body = futures_util::stream::unfold((events, false), move |(mut events, done)| {
+        let notification_id = notification_id.clone();
+        let correlation_id = correlation_id.clone();
+        async move {
+            if done {
+                return None;
+            }
+            loop {
+                let payload = events.next().await?;
+                let event: Value = match serde_json::from_str(&payload) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                if event["notification_id"].as_str() != Some(notification_id.as_str()) {
+                    continue;
+                }
+
+                let terminal = matches!(event["status"].as_str(), Some("delivered") | Some("failed"));
+                let sse = format!("id: {correlation_id}\nevent: status\ndata: {event}\n\n");
+                return Some((
+                    Ok::<_, actix_web::Error>(web::Bytes::from(sse)),
+                    (events, terminal),
+                ));
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("cache-control", "no-cache"))
+        .streaming(body)
+}
@@ -2,8 +2,8 @@ use actix_web::{middleware::Logger, web, App, HttpResponse, HttpServer, Responde
 use anyhow::Result;
 use dotenvy::dotenv;
 use lapin::{
-    options::{BasicQosOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions},
-    types::FieldTable,
+    options::{BasicQosOptions, ConfirmSelectOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions, QueueDeleteOptions},
+    types::{AMQPValue, FieldTable},
     Channel, Connection, ConnectionProperties, ExchangeKind,
 };
 use std::{
@@ -13,7 +13,10 @@ use std::{
 };
 use tokio::time::sleep;
 
+mod apns;
 mod handlers;
+mod retry;
+mod rpc;
 mod status;
 mod middleware;
 mod models;
@@ -22,6 +25,16 @@ use handlers::{AppState as HState};
 use middleware::CorrelationId;
 use status::StatusStore;
 
+// Kept unversioned: the email worker deployed alongside this gateway binds
+// to "email.queue" by that literal name and isn't something we can rename
+// out from under. When the declare arguments change (as they did for
+// `x-dead-letter-*`/`x-max-priority`), `declare_queue_migrating` below
+// handles the 406 PRECONDITION_FAILED this would otherwise cause by
+// redeclaring the queue in place on a fresh channel, instead of us
+// renaming it.
+pub const EMAIL_QUEUE: &str = "email.queue";
+pub const PUSH_QUEUE: &str = "push.queue";
+
 // ---------- health ----------
 async fn health() -> impl Responder {
     HttpResponse::Ok().json(serde_json::json!({
@@ -55,7 +68,32 @@ async fn connect_with_retry(amqp_url: &str) -> Connection {
     }
 }
 
-async fn declare_topology(channel: &Channel, exchange: &str) -> Result<()> {
+/// Declares `queue` with `args`, recovering from a stale declaration rather
+/// than renaming the queue — external consumers (e.g. the email worker) are
+/// bound to these names by convention and aren't ours to move.
+///
+/// A failed `queue_declare` here is a channel-level exception (406
+/// PRECONDITION_FAILED), which closes `*channel` server-side, so the
+/// retry has to happen on a fresh channel. The redeclare only deletes the
+/// queue `if_empty`: a production `email.queue`/`push.queue` can be
+/// holding live, undelivered notifications, and a redeploy that changes
+/// declare args must not silently discard them — if the queue isn't
+/// empty this errors out and aborts startup instead, so the backlog can
+/// be drained manually before the new arguments are rolled out.
+async fn declare_queue_migrating(conn: &Connection, channel: &mut Channel, queue: &str, args: FieldTable) -> Result<()> {
+    let opts = QueueDeclareOptions { passive: false, durable: true, auto_delete: false, exclusive: false, nowait: false };
+    if channel.queue_declare(queue, opts, args.clone()).await.is_ok() {
+        return Ok(());
+    }
+
+    eprintln!("queue_declare({queue}) failed (likely stale arguments from an older deploy); opening a fresh channel to delete and redeclare");
+    *channel = conn.create_channel().await?;
+    channel.queue_delete(queue, QueueDeleteOptions { if_unused: false, if_empty: true, nowait: false }).await?;
+    channel.queue_declare(queue, opts, args).await?;
+    Ok(())
+}
+
+async fn declare_topology(conn: &Connection, channel: &mut Channel, exchange: &str, max_priority: u8) -> Result<()> {
     channel.exchange_declare(
         exchange,
         ExchangeKind::Direct,
@@ -63,17 +101,38 @@ async fn declare_topology(channel: &Channel, exchange: &str) -> Result<()> {
         FieldTable::default(),
     ).await?;
 
-    for q in ["email.queue", "push.queue", "failed.queue"] {
-        channel.queue_declare(
-            q,
-            QueueDeclareOptions { passive: false, durable: true, auto_delete: false, exclusive: false, nowait: false },
-            FieldTable::default(),
-        ).await?;
+    channel.exchange_declare(
+        retry::DLX_EXCHANGE,
+        ExchangeKind::Direct,
+        ExchangeDeclareOptions { passive: false, durable: true, auto_delete: false, internal: false, nowait: false },
+        FieldTable::default(),
+    ).await?;
+
+    let mut queue_args = FieldTable::default();
+    queue_args.insert("x-dead-letter-exchange".into(), AMQPValue::LongString(retry::DLX_EXCHANGE.into()));
+    queue_args.insert("x-dead-letter-routing-key".into(), AMQPValue::LongString("failed".into()));
+    // Unsigned field type: `max_priority` is a `u8` end to end (RabbitMQ's
+    // `x-max-priority` accepts 0..=255), so encoding it as `ShortShortInt`
+    // (a signed byte) would wrap negative for any operator-configured value
+    // above 127.
+    queue_args.insert("x-max-priority".into(), AMQPValue::ShortShortUInt(max_priority));
+
+    for q in [EMAIL_QUEUE, PUSH_QUEUE] {
+        declare_queue_migrating(conn, channel, q, queue_args.clone()).await?;
     }
 
-    channel.queue_bind("email.queue", exchange, "email", QueueBindOptions { nowait: false }, FieldTable::default()).await?;
-    channel.queue_bind("push.queue",  exchange, "push",  QueueBindOptions { nowait: false }, FieldTable::default()).await?;
+    channel.queue_declare(
+        "failed.queue",
+        QueueDeclareOptions { passive: false, durable: true, auto_delete: false, exclusive: false, nowait: false },
+        FieldTable::default(),
+    ).await?;
+
+    channel.queue_bind(EMAIL_QUEUE, exchange, "email", QueueBindOptions { nowait: false }, FieldTable::default()).await?;
+    channel.queue_bind(PUSH_QUEUE,  exchange, "push",  QueueBindOptions { nowait: false }, FieldTable::default()).await?;
+    channel.queue_bind("failed.queue", retry::DLX_EXCHANGE, "failed", QueueBindOptions { nowait: false }, FieldTable::default()).await?;
     channel.basic_qos(0, BasicQosOptions { global: true }).await?;
+
+    retry::declare_retry_topology(channel, exchange).await?;
     Ok(())
 }
 
@@ -94,14 +153,47 @@ async fn main() -> Result<()> {
 
     let idem_ttl_secs: u64 = env::var("IDEM_TTL_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(86_400);
     let status_ttl_secs: u64 = env::var("STATUS_TTL_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(86_400);
+    let max_priority: u8 = env::var("MAX_PRIORITY").ok().and_then(|s| s.parse().ok()).unwrap_or(10);
 
     // Persistent RabbitMQ connect loop
     let amqp_ready = Arc::new(AtomicBool::new(false));
     let conn = connect_with_retry(&amqp_url).await;
-    let channel = conn.create_channel().await?;
-    declare_topology(&channel, &exchange_name).await?;
+    let mut channel = conn.create_channel().await?;
+    declare_topology(&conn, &mut channel, &exchange_name, max_priority).await?;
+    // Publisher confirms: create_notification_core awaits the PublisherConfirm
+    // returned by basic_publish to roll back the idempotency reservation on a
+    // broker-level nack. Without selecting confirm mode that future resolves
+    // immediately as `NotRequested`, which create_notification_core treats
+    // as a failed publish.
+    channel.confirm_select(ConfirmSelectOptions::default()).await?;
     amqp_ready.store(true, Ordering::SeqCst);
 
+    // Dead-letter retry/backoff: always on, reuses GATEWAY_SELF_URL for status callbacks
+    let gateway_self_url =
+        env::var("GATEWAY_SELF_URL").unwrap_or_else(|_| "http://127.0.0.1:8080".to_string());
+    let retry_channel = conn.create_channel().await?;
+    let retry_self_url = gateway_self_url.clone();
+    tokio::spawn(async move {
+        if let Err(e) = retry::run_retry_consumer(retry_channel, retry_self_url).await {
+            eprintln!("retry consumer exited: {e}");
+        }
+    });
+
+    // APNs push delivery: optional, disabled when the provider credentials aren't configured
+    match apns::ApnsConfig::from_env().and_then(apns::ApnsClient::new) {
+        Ok(client) => {
+            let apns_channel = conn.create_channel().await?;
+            let apns_self_url = gateway_self_url.clone();
+            let apns_template_svc_url = template_svc_url.clone();
+            tokio::spawn(async move {
+                if let Err(e) = apns::run_push_consumer(apns_channel, client, apns_self_url, apns_template_svc_url).await {
+                    eprintln!("apns push consumer exited: {e}");
+                }
+            });
+        }
+        Err(e) => eprintln!("APNs push delivery disabled: {e}"),
+    }
+
     // Redis
     let status_store = StatusStore::new(&redis_url, idem_ttl_secs, status_ttl_secs);
 
@@ -113,6 +205,7 @@ async fn main() -> Result<()> {
         template_svc_url,
         status_store,
         amqp_ready: amqp_ready.clone(),
+        max_priority,
     });
 
     // HTTP
@@ -126,9 +219,11 @@ async fn main() -> Result<()> {
             .service(
                 web::scope("/api/v1")
                     .route("/notifications/", web::post().to(handlers::create_notification))
+                    .route("/notifications/{id}/events", web::get().to(handlers::stream_notification_events))
                     .route("/users/",         web::post().to(handlers::create_user))
                     .route("/email/status/",  web::post().to(handlers::update_status_email))
                     .route("/push/status/",   web::post().to(handlers::update_status_push))
+                    .route("/rpc",            web::post().to(rpc::rpc))
             )
     })
     .bind(http_addr)?
@@ -0,0 +1,187 @@
+use anyhow::Result;
+use futures_util::stream::StreamExt;
+use lapin::{
+    options::{BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, QueueDeclareOptions},
+    protocol::basic::AMQPProperties,
+    types::{AMQPValue, FieldTable},
+    Channel,
+};
+use reqwest::Client;
+use serde_json::Value;
+
+use crate::models::{NotificationStatus, UpdateStatusRequest};
+
+/// Exchange that `email.queue`/`push.queue` dead-letter into on nack, ahead
+/// of `failed.queue`.
+pub const DLX_EXCHANGE: &str = "notifications.failed";
+
+// One delay queue per routing key so each can carry a fixed
+// `x-dead-letter-routing-key`, routing a retried message straight back to
+// the channel it came from once its `expiration` elapses.
+const DELAY_QUEUES: [(&str, &str); 2] = [("email.delay", "email"), ("push.delay", "push")];
+
+const BASE_DELAY_MS: u64 = 1_000;
+const MAX_DELAY_MS: u64 = 60_000;
+
+/// Declares the per-channel delay queues backing retry/backoff. Called by
+/// `declare_topology` alongside the main exchange/queue setup.
+pub async fn declare_retry_topology(channel: &Channel, exchange: &str) -> Result<()> {
+    for (queue, routing_key) in DELAY_QUEUES {
+        let mut args = FieldTable::default();
+        args.insert("x-dead-letter-exchange".into(), AMQPValue::LongString(exchange.into()));
+        args.insert(
+            "x-dead-letter-routing-key".into(),
+            AMQPValue::LongString(routing_key.into()),
+        );
+
+        channel
+            .queue_declare(
+                queue,
+                QueueDeclareOptions {
+                    passive: false,
+                    durable: true,
+                    auto_delete: false,
+                    exclusive: false,
+                    nowait: false,
+                },
+                args,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+fn delay_queue_for(routing_key: &str) -> &'static str {
+    DELAY_QUEUES
+        .iter()
+        .find(|(_, rk)| *rk == routing_key)
+        .map(|(q, _)| *q)
+        .unwrap_or("email.delay")
+}
+
+/// 1s, 2s, 4s, … capped at `MAX_DELAY_MS`.
+fn backoff_delay_ms(attempt: u32) -> u64 {
+    BASE_DELAY_MS.saturating_mul(1u64 << attempt.min(6)).min(MAX_DELAY_MS)
+}
+
+/// Consumes `failed.queue` (the dead-letter target for nacked `email.queue`/
+/// `push.queue` messages), bumps `attempt`, and either republishes to the
+/// matching delay queue with an exponential `expiration`, or — once
+/// `max_attempts` is reached — reports the notification `failed` back to
+/// the gateway. Per-delivery errors are logged and skipped rather than
+/// propagated, so one bad message can't take down the whole consumer.
+pub async fn run_retry_consumer(channel: Channel, gateway_base_url: String) -> Result<()> {
+    let mut consumer = channel
+        .basic_consume(
+            "failed.queue",
+            "retry-worker",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let status_client = Client::new();
+    while let Some(delivery) = consumer.next().await {
+        let delivery = match delivery {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("failed.queue consume error: {e}");
+                continue;
+            }
+        };
+
+        let mut msg: Value = match serde_json::from_slice(&delivery.data) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("failed.queue bad payload: {e}");
+                if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
+                    eprintln!("failed.queue ack error: {e}");
+                }
+                continue;
+            }
+        };
+
+        let attempt = msg["attempt"].as_u64().unwrap_or(0) as u32;
+        let max_attempts = msg["max_attempts"].as_u64().unwrap_or(3) as u32;
+        let next_attempt = attempt + 1;
+        msg["attempt"] = serde_json::json!(next_attempt);
+
+        let routing_key = msg["notification_type"].as_str().unwrap_or("email").to_string();
+        let request_id = msg["request_id"].as_str().unwrap_or_default().to_string();
+
+        if next_attempt < max_attempts {
+            let delay = backoff_delay_ms(attempt);
+            let payload = match serde_json::to_vec(&msg) {
+                Ok(p) => p,
+                Err(e) => {
+                    eprintln!("failed to serialize retry payload for {request_id}: {e}");
+                    continue;
+                }
+            };
+
+            // Re-derive priority/correlation_id from the payload: once this
+            // round-trips through the delay queue back into email.queue/
+            // push.queue, any AMQP property not carried over here is gone
+            // for good, silently losing the priority-jump-the-queue
+            // behavior for every notification that's failed even once.
+            let priority = msg["priority"].as_u64().unwrap_or(0) as u8;
+            let mut props = AMQPProperties::default()
+                .with_content_type("application/json".into())
+                .with_expiration(delay.to_string().into())
+                .with_priority(priority);
+            if let Some(correlation_id) = msg["correlation_id"].as_str() {
+                props = props.with_correlation_id(correlation_id.to_string().into());
+            }
+
+            let republish = async {
+                channel
+                    .basic_publish(
+                        "",
+                        delay_queue_for(&routing_key),
+                        BasicPublishOptions::default(),
+                        &payload,
+                        props,
+                    )
+                    .await?
+                    .await
+            }
+            .await;
+
+            match republish {
+                Ok(_) => {
+                    if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
+                        eprintln!("failed.queue ack error for {request_id}: {e}");
+                    }
+                }
+                Err(e) => {
+                    // Don't ack: a transient publish failure shouldn't drop
+                    // the message, so leave it to be redelivered instead.
+                    eprintln!("failed to republish {request_id} to delay queue: {e}");
+                }
+            }
+        } else {
+            let update = UpdateStatusRequest {
+                notification_id: request_id.clone(),
+                status: NotificationStatus::Failed,
+                timestamp: Some(chrono::Utc::now().to_rfc3339()),
+                error: Some("max_attempts_exceeded".to_string()),
+            };
+            let url = format!("{gateway_base_url}/api/v1/{routing_key}/status/");
+            if let Err(e) = status_client.post(&url).json(&update).send().await {
+                eprintln!("failed to post dead-letter status for {request_id}: {e}");
+            }
+            // Ack regardless: the bumped `attempt` only ever lives in this
+            // local `msg`, never written back to the queued delivery, so
+            // leaving it unacked as an "audit trail" means a reconnect or
+            // restart requeues the same exhausted message, which recomputes
+            // the same max_attempts check and re-posts a duplicate `failed`
+            // status forever. Acking here accepts the queue no longer
+            // doubles as that record.
+            if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
+                eprintln!("failed.queue ack error for {request_id}: {e}");
+            }
+        }
+    }
+
+    Ok(())
+}
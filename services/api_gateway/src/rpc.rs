@@ -0,0 +1,150 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::handlers::{create_notification_core, AppState};
+use crate::models::CreateNotificationRequest;
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const INTERNAL_ERROR: i32 = -32603;
+
+#[derive(Debug, Deserialize)]
+struct RpcCall {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+impl RpcResponse {
+    fn ok(id: Value, result: Value) -> Self {
+        Self { jsonrpc: "2.0", result: Some(result), error: None, id }
+    }
+    fn err(id: Value, code: i32, message: impl Into<String>) -> Self {
+        Self { jsonrpc: "2.0", result: None, error: Some(RpcError { code, message: message.into() }), id }
+    }
+}
+
+/// Dispatches a single already-parsed call to the matching handler logic.
+/// Returns `None` for notification-style calls (no `id`), per the JSON-RPC
+/// spec — those never get a response, batched or not.
+async fn dispatch(state: &web::Data<AppState>, call: RpcCall) -> Option<RpcResponse> {
+    let id = call.id.clone().unwrap_or(Value::Null);
+    let has_id = call.id.is_some();
+
+    if call.jsonrpc != "2.0" {
+        return has_id.then(|| RpcResponse::err(id, INVALID_REQUEST, "jsonrpc must be \"2.0\""));
+    }
+
+    let result = match call.method.as_str() {
+        "notifications.create" => match serde_json::from_value::<CreateNotificationRequest>(call.params) {
+            Ok(req) => {
+                let (status, envelope) = create_notification_core(state, req).await;
+                if status.is_success() {
+                    Ok(serde_json::to_value(&envelope.data).unwrap_or(Value::Null))
+                } else if status.is_client_error() {
+                    Err((INVALID_PARAMS, envelope.error.unwrap_or(envelope.message)))
+                } else {
+                    Err((INTERNAL_ERROR, envelope.error.unwrap_or(envelope.message)))
+                }
+            }
+            Err(e) => Err((INVALID_PARAMS, format!("invalid notifications.create params: {e}"))),
+        },
+        "notifications.status" => match call.params.get("notification_id").and_then(Value::as_str) {
+            Some(notification_id) => match state.status_store.get_status(notification_id).await {
+                Ok(Some(status)) => Ok(json!({ "notification_id": notification_id, "status": status })),
+                Ok(None) => Err((INTERNAL_ERROR, "unknown notification_id".to_string())),
+                Err(e) => Err((INTERNAL_ERROR, format!("redis_error: {e}"))),
+            },
+            None => Err((INVALID_PARAMS, "params.notification_id is required".to_string())),
+        },
+        other => Err((METHOD_NOT_FOUND, format!("unknown method: {other}"))),
+    };
+
+    if !has_id {
+        return None;
+    }
+
+    Some(match result {
+        Ok(value) => RpcResponse::ok(id, value),
+        Err((code, message)) => RpcResponse::err(id, code, message),
+    })
+}
+
+// POST /api/v1/rpc — JSON-RPC 2.0, accepting either a single call or a
+// batch array so callers can reserve idempotency and publish hundreds of
+// notifications in one round trip.
+//
+// Takes raw `Bytes` rather than `web::Json<Value>` so that genuinely
+// malformed JSON is parsed here and reported as a -32700 PARSE_ERROR
+// envelope, instead of actix's extractor rejecting it first with a plain
+// (non-JSON-RPC) 400 before this handler ever runs.
+pub async fn rpc(state: web::Data<AppState>, body: web::Bytes) -> HttpResponse {
+    let body: Value = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return HttpResponse::Ok().json(RpcResponse::err(Value::Null, PARSE_ERROR, e.to_string()));
+        }
+    };
+
+    // Spec: an empty batch array is itself an invalid request, reported as
+    // a single (non-array) error object — not dispatched as zero calls.
+    if matches!(&body, Value::Array(items) if items.is_empty()) {
+        return HttpResponse::Ok().json(RpcResponse::err(Value::Null, INVALID_REQUEST, "empty batch"));
+    }
+
+    let is_batch = matches!(body, Value::Array(_));
+    let calls: Vec<Value> = match body {
+        Value::Array(items) => items,
+        single => vec![single],
+    };
+
+    let mut responses = Vec::new();
+    for call in calls {
+        // The call parsed as JSON but doesn't deserialize into `RpcCall`
+        // (missing/misshaped `method`/`jsonrpc`) — per spec that's still a
+        // well-formed JSON value, just not a valid Request object, so it's
+        // INVALID_REQUEST (-32600), not PARSE_ERROR (-32700).
+        match serde_json::from_value::<RpcCall>(call) {
+            Ok(parsed) => {
+                if let Some(resp) = dispatch(&state, parsed).await {
+                    responses.push(resp);
+                }
+            }
+            Err(e) => responses.push(RpcResponse::err(Value::Null, INVALID_REQUEST, e.to_string())),
+        }
+    }
+
+    // Spec: when a batch (or a lone notification-style call) produces no
+    // Response objects, the server must return nothing at all — not `[]`.
+    if responses.is_empty() {
+        return HttpResponse::NoContent().finish();
+    }
+
+    if is_batch {
+        HttpResponse::Ok().json(responses)
+    } else {
+        HttpResponse::Ok().json(responses.into_iter().next().expect("checked non-empty above"))
+    }
+}
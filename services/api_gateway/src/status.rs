@@ -1,11 +1,23 @@
+use std::pin::Pin;
+
 use anyhow::Result;
 use deadpool_redis::{Config, Pool, Runtime};
-use deadpool_redis::redis;                        
-use deadpool_redis::redis::AsyncCommands; 
+use deadpool_redis::redis;
+use deadpool_redis::redis::AsyncCommands;
+use futures_util::{Stream, StreamExt};
+
+// Window a reservation has to be committed or rolled back in, i.e. how long
+// the mandatory `basic_publish` confirm is allowed to take.
+const PENDING_IDEM_TTL: usize = 30;
+
+// Redis pub/sub channel `set_status` publishes to so SSE subscribers get
+// status transitions as they happen instead of polling.
+const STATUS_EVENTS_CHANNEL: &str = "status-events";
 
 #[derive(Clone)]
 pub struct StatusStore {
     pool: Pool,
+    redis_url: String,
     idem_ttl: usize,
     status_ttl: usize,
 }
@@ -16,40 +28,89 @@ impl StatusStore {
         let pool = cfg.create_pool(Some(Runtime::Tokio1)).expect("redis pool");
         Self {
             pool,
+            redis_url: redis_url.to_string(),
             idem_ttl: idem_ttl_secs as usize,
             status_ttl: status_ttl_secs as usize,
         }
     }
 
-    /// Reserve idempotency key; returns true if we reserved, false if duplicate.
+    /// Reserve idempotency key with a short "pending-publish" marker; returns
+    /// true if we reserved, false if duplicate. Callers must follow up with
+    /// `commit_idem` once the publish is confirmed, or `rollback_idem` if it
+    /// fails, so a failed enqueue doesn't permanently claim the key.
     pub async fn reserve_idem(&self, req_id: &str) -> Result<bool> {
         let mut conn = self.pool.get().await?;
         // SETNX + EX
         let key = format!("idem:{}", req_id);
         let created: bool = redis::cmd("SET")
-            .arg(&key).arg("1")
+            .arg(&key).arg("pending")
             .arg("NX")
-            .arg("EX").arg(self.idem_ttl)
+            .arg("EX").arg(PENDING_IDEM_TTL)
             .query_async(&mut *conn).await
             .unwrap_or(false);
         Ok(created)
     }
 
-    pub async fn set_status(&self, notification_id: &str, state: &str) -> Result<()> {
+    /// Promotes a reserved key to the full `idem_ttl` once the mandatory
+    /// publish is confirmed, so a genuine duplicate request is deduplicated.
+    pub async fn commit_idem(&self, req_id: &str) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        let key = format!("idem:{}", req_id);
+        let _: () = redis::pipe()
+            .cmd("SET").arg(&key).arg("1").ignore()
+            .cmd("EXPIRE").arg(&key).arg(self.idem_ttl).ignore()
+            .query_async(&mut *conn).await?;
+        Ok(())
+    }
+
+    /// Releases a reserved key when the publish fails, so the caller's
+    /// legitimate retry with the same request id isn't swallowed as a
+    /// `duplicate_request`.
+    pub async fn rollback_idem(&self, req_id: &str) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        let key = format!("idem:{}", req_id);
+        let _: () = conn.del(key).await?;
+        Ok(())
+    }
+
+    /// Sets the point-in-time status and publishes the same transition on
+    /// `status-events` so `subscribe` callers get it live instead of polling.
+    pub async fn set_status(&self, notification_id: &str, state: &str, channel: Option<&str>) -> Result<()> {
         let mut conn = self.pool.get().await?;
         let key = format!("status:{}", notification_id);
+        let event = serde_json::json!({
+            "notification_id": notification_id,
+            "status": state,
+            "channel": channel,
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+        });
+        let payload = serde_json::to_string(&event)?;
         let _: () = redis::pipe()
             .cmd("SET").arg(&key).arg(state).ignore()
             .cmd("EXPIRE").arg(&key).arg(self.status_ttl).ignore()
+            .cmd("PUBLISH").arg(STATUS_EVENTS_CHANNEL).arg(&payload).ignore()
             .query_async(&mut *conn).await?;
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub async fn get_status(&self, notification_id: &str) -> Result<Option<String>> {
         let mut conn = self.pool.get().await?;
         let key = format!("status:{}", notification_id);
         let v: Option<String> = conn.get(key).await?;
         Ok(v)
     }
+
+    /// Opens a dedicated pub/sub connection (outside the pool, as pub/sub
+    /// connections can't be reused for commands) and streams raw
+    /// `status-events` JSON payloads as they're published.
+    pub async fn subscribe(&self) -> Result<Pin<Box<dyn Stream<Item = String> + Send>>> {
+        let client = redis::Client::open(self.redis_url.clone())?;
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(STATUS_EVENTS_CHANNEL).await?;
+
+        let stream = pubsub
+            .into_on_message()
+            .filter_map(|msg| async move { msg.get_payload::<String>().ok() });
+        Ok(Box::pin(stream))
+    }
 }